@@ -0,0 +1,98 @@
+//! Fan a single call out to many already-connected clients.
+
+use futures::{stream::FuturesUnordered, Future, Stream};
+use jsonrpc_core::Params;
+use serde_json::Value;
+
+use crate::{RawClient, RpcError};
+
+/// Call `method` with `params` on every client in `clients` concurrently,
+/// collecting the results in the same order `clients` was given.
+///
+/// Useful for quorum reads, health-probing a pool of RPC nodes, or comparing
+/// responses across replicas. Each client keeps whatever timeout it was
+/// built with, so a slow peer's call ends in its own slot as that client's
+/// timeout error; the returned vector resolves only once every call has
+/// finished or timed out.
+pub fn call_many(
+	clients: &[RawClient],
+	method: &str,
+	params: Params,
+) -> impl Future<Item=Vec<Result<Value, RpcError>>, Error=RpcError> {
+	let calls: FuturesUnordered<_> = clients
+		.iter()
+		.enumerate()
+		.map(|(index, client)| {
+			client.call_method(method, params.clone()).then(move |result| Ok((index, result)))
+		})
+		.collect();
+
+	calls
+		.collect()
+		.map(|mut indexed: Vec<(usize, Result<Value, RpcError>)>| {
+			indexed.sort_by_key(|(index, _)| *index);
+			indexed.into_iter().map(|(_, result)| result).collect()
+		})
+		.map_err(|()| unreachable!("each call's error is folded into its Ok(Err(..)) slot above"))
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::Duration;
+
+	use failure::format_err;
+	use hyper::rt;
+
+	use super::*;
+	use crate::RpcMessage;
+
+	/// A `RawClient` backed by a handler instead of a real transport, so
+	/// `call_many` can be tested without spinning up a server per client.
+	fn stub_client<F>(handler: F) -> impl Future<Item=RawClient, Error=RpcError>
+	where
+		F: Fn(&str, Params) -> Result<Value, RpcError> + Send + 'static,
+	{
+		rt::lazy(move || {
+			let (sender, receiver) = futures::sync::mpsc::channel(0);
+			let task = receiver.for_each(move |msg: RpcMessage| {
+				if let RpcMessage::Call(call) = msg {
+					let _ = call.sender.send(handler(&call.method, call.params));
+				}
+				Ok(())
+			});
+			rt::spawn(task);
+			Ok(RawClient::from(sender))
+		})
+	}
+
+	#[test]
+	fn preserves_order_and_folds_per_client_errors() {
+		crate::logger::init_log();
+
+		// given: the middle client always fails, the other two succeed
+		let (tx, rx) = std::sync::mpsc::channel();
+		let run = stub_client(|_, _| Ok(Value::String("pong-a".into())))
+			.join3(
+				stub_client(|_, _| Err(RpcError::Other(format_err!("client b is down")))),
+				stub_client(|_, _| Ok(Value::String("pong-c".into()))),
+			)
+			.and_then(move |(a, b, c)| {
+				call_many(&[a, b, c], "ping", Params::None)
+					.then(move |result| {
+						let _ = tx.send(result);
+						Ok(())
+					})
+			})
+			.map_err(|e: RpcError| log::error!("stub client error: {:?}", e));
+
+		// when
+		rt::run(run);
+
+		// then: results line up with the clients they came from, in order
+		let results = rx.recv_timeout(Duration::from_secs(3)).unwrap().unwrap();
+		assert_eq!(results.len(), 3);
+		assert_eq!(results[0].as_ref().unwrap(), &Value::String("pong-a".into()));
+		assert!(results[1].is_err());
+		assert_eq!(results[2].as_ref().unwrap(), &Value::String("pong-c".into()));
+	}
+}
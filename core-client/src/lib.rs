@@ -0,0 +1,173 @@
+//! JSON-RPC client implementation built on top of `futures` 0.1.
+//!
+//! A transport (see [`transports`]) turns a stream of [`RpcMessage`]s into
+//! requests against a server and resolves each message's `sender` with the
+//! matching response. [`TypedClient`] is a thin, ergonomic wrapper around an
+//! [`RpcChannel`] for callers who want to describe calls in terms of
+//! serializable arguments and deserializable results instead of raw
+//! `Params`/`Value`.
+
+#![deny(missing_docs)]
+
+use failure::Fail;
+use futures::{sync::{mpsc, oneshot}, Future, Sink};
+use jsonrpc_core::{Error as JsonRpcError, Params};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+
+pub mod transports;
+
+pub mod broadcast;
+pub mod logger;
+
+/// A single call, paired with the channel its result should be delivered on.
+pub struct CallMessage {
+	/// The method to call.
+	pub method: String,
+	/// The method's parameters.
+	pub params: Params,
+	/// Channel the transport uses to deliver the result back to the caller.
+	pub sender: oneshot::Sender<Result<Value, RpcError>>,
+}
+
+/// Several calls dispatched together as a single `jsonrpc_core::Request::Batch`,
+/// paired with the channel their (order-preserving) results should be delivered on.
+pub struct BatchMessage {
+	/// The methods and parameters to call, in call order.
+	pub calls: Vec<(String, Params)>,
+	/// Channel the transport uses to deliver the batch's results back to the caller.
+	pub sender: oneshot::Sender<Vec<Result<Value, RpcError>>>,
+}
+
+/// A message sent to a transport, describing either a single call or a batch
+/// of calls to make.
+pub enum RpcMessage {
+	/// A single call.
+	Call(CallMessage),
+	/// A batch of calls, sent together as one `Request::Batch`.
+	Batch(BatchMessage),
+}
+
+impl RpcMessage {
+	/// Build a single-call message.
+	pub fn call(method: String, params: Params, sender: oneshot::Sender<Result<Value, RpcError>>) -> Self {
+		RpcMessage::Call(CallMessage { method, params, sender })
+	}
+}
+
+/// A channel to a transport, used to dispatch [`RpcMessage`]s to it.
+pub type RpcChannel = mpsc::Sender<RpcMessage>;
+
+/// A client for making raw JSON-RPC calls, without (de)serializing arguments
+/// or results. Mostly useful for generic tooling such as [`broadcast::call_many`].
+#[derive(Clone)]
+pub struct RawClient(RpcChannel);
+
+impl From<RpcChannel> for RawClient {
+	fn from(channel: RpcChannel) -> Self {
+		RawClient(channel)
+	}
+}
+
+impl RawClient {
+	/// Call a method with raw `Params`, returning the raw result `Value`.
+	pub fn call_method(&self, method: &str, params: Params) -> impl Future<Item=Value, Error=RpcError> {
+		let (sender, receiver) = oneshot::channel();
+		let msg = RpcMessage::call(method.to_owned(), params, sender);
+
+		self.0
+			.clone()
+			.send(msg)
+			.map_err(|e| RpcError::Other(e.into()))
+			.and_then(|_| receiver.map_err(|e| RpcError::Other(e.into())))
+			.and_then(|result| result)
+	}
+}
+
+/// A typed client that serializes call arguments and deserializes results.
+#[derive(Clone)]
+pub struct TypedClient(RpcChannel);
+
+impl From<RpcChannel> for TypedClient {
+	fn from(channel: RpcChannel) -> Self {
+		TypedClient(channel)
+	}
+}
+
+impl TypedClient {
+	/// Create a new `TypedClient` from a raw `RpcChannel`.
+	pub fn new(channel: RpcChannel) -> Self {
+		TypedClient(channel)
+	}
+
+	fn params_from<T: Serialize>(args: T) -> Params {
+		match serde_json::to_value(args).expect("Only infallible argument serialization is supported") {
+			Value::Array(vec) => Params::Array(vec),
+			Value::Null => Params::None,
+			value => Params::Array(vec![value]),
+		}
+	}
+
+	/// Call a method with serializable arguments, deserializing the result as `R`.
+	pub fn call_method<T: Serialize, R: DeserializeOwned>(
+		&self,
+		method: &str,
+		_returns: &'static str,
+		args: T,
+	) -> impl Future<Item=R, Error=RpcError> {
+		let (sender, receiver) = oneshot::channel();
+		let msg = RpcMessage::call(method.to_owned(), Self::params_from(args), sender);
+
+		self.0
+			.clone()
+			.send(msg)
+			.map_err(|e| RpcError::Other(e.into()))
+			.and_then(|_| receiver.map_err(|e| RpcError::Other(e.into())))
+			.and_then(|result| result)
+			.and_then(|value| {
+				serde_json::from_value::<R>(value)
+					.map_err(|e| RpcError::ParseError(e.to_string(), e.into()))
+			})
+	}
+
+	/// Call several methods as a single batched request, sent as one
+	/// `Request::Batch` and demultiplexed back into a `Vec` of results in
+	/// the same order the calls were given.
+	pub fn call_batch(
+		&self,
+		calls: Vec<(&str, Params)>,
+	) -> impl Future<Item=Vec<Result<Value, RpcError>>, Error=RpcError> {
+		let (sender, receiver) = oneshot::channel();
+		let calls = calls.into_iter().map(|(method, params)| (method.to_owned(), params)).collect();
+		let msg = RpcMessage::Batch(BatchMessage { calls, sender });
+
+		self.0
+			.clone()
+			.send(msg)
+			.map_err(|e| RpcError::Other(e.into()))
+			.and_then(|_| receiver.map_err(|e| RpcError::Other(e.into())))
+	}
+}
+
+/// The errors returned by a client.
+#[derive(Debug, Fail)]
+pub enum RpcError {
+	/// An error returned by the server.
+	#[fail(display = "JSON-RPC error: {}", _0)]
+	JsonRpcError(JsonRpcError),
+	/// Failure to parse server response.
+	#[fail(display = "Failed to parse server response: {}", _0)]
+	ParseError(String, #[fail(cause)] failure::Error),
+	/// A request did not complete within its configured timeout.
+	#[fail(display = "Request timed out")]
+	Timeout,
+	/// Any other error.
+	#[fail(display = "{}", _0)]
+	Other(#[fail(cause)] failure::Error),
+}
+
+impl From<JsonRpcError> for RpcError {
+	fn from(error: JsonRpcError) -> Self {
+		RpcError::JsonRpcError(error)
+	}
+}
@@ -0,0 +1,10 @@
+//! Test helper for enabling logging output.
+
+/// Initialize the logger once, ignoring subsequent calls.
+///
+/// Several tests spin up their own HTTP server and client; calling this at
+/// the start of each lets `RUST_LOG=trace cargo test -- --nocapture` show
+/// what the transport is doing without double-initializing the logger.
+pub fn init_log() {
+	let _ = env_logger::try_init();
+}
@@ -1,51 +1,229 @@
 //! HTTP client
 
+use std::{
+	sync::{atomic::{AtomicUsize, Ordering}, Arc, Mutex},
+	time::{Duration, Instant},
+};
+
 use failure::format_err;
 use futures::{
 	future::{self, Either::{A, B}},
-	sync::mpsc,
+	sync::{mpsc, oneshot},
 	Future,
 	Stream
 };
-use hyper::{http, rt, Client, Request};
-use jsonrpc_core::{self, Call, Error, Id, MethodCall, Output, Params, Response, Version};
+use hyper::{client::connect::Connect, http, rt, Body, Client, Request, Response as HyperResponse};
+use jsonrpc_core::{self, Call, Id, MethodCall, Response, Version};
+use tokio_timer::{Delay, Timeout};
 
 use crate::{RpcChannel, RpcError, RpcMessage};
-use super::request_response;
+use super::{request_response, Pending};
+use super::tls::{https_connector, TlsConfig};
 use futures::sink::Sink;
 
+/// Total number of connection attempts (the first one included) before a call gives up.
+const MAX_CONNECT_ATTEMPTS: usize = 3;
+/// How long to wait between connection attempts.
+const CONNECT_RETRY_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Configuration for an HTTP(S) client.
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+	/// Maximum number of requests in flight at once.
+	pub max_parallel: usize,
+	/// How long each connection attempt waits for the response headers, and
+	/// then how long reading the response body may take, before the request
+	/// fails with `RpcError::Timeout`.
+	pub request_timeout: Duration,
+}
+
+impl Default for ClientConfig {
+	fn default() -> Self {
+		ClientConfig {
+			max_parallel: 8,
+			request_timeout: Duration::from_secs(30),
+		}
+	}
+}
+
 /// Create a HTTP Client
 pub fn http<TClient>(url: &str) -> impl Future<Item=TClient, Error=RpcError>
 where
 	TClient: From<RpcChannel>,
 {
-	let max_parallel = 8;
-	let url = url.to_owned();
-	let client = Client::new();
+	http_with_config(url, ClientConfig::default())
+}
+
+/// Create a HTTP Client with a custom `ClientConfig`.
+pub fn http_with_config<TClient>(url: &str, config: ClientConfig) -> impl Future<Item=TClient, Error=RpcError>
+where
+	TClient: From<RpcChannel>,
+{
+	with_client(Client::new(), url.to_owned(), config)
+}
+
+/// Create a HTTPS Client, verifying the server (and optionally authenticating
+/// itself) according to `tls`.
+pub fn https<TClient>(url: &str, tls: TlsConfig) -> impl Future<Item=TClient, Error=RpcError>
+where
+	TClient: From<RpcChannel>,
+{
+	https_with_config(url, tls, ClientConfig::default())
+}
+
+/// Create a HTTPS Client with a custom `ClientConfig`.
+pub fn https_with_config<TClient>(
+	url: &str,
+	tls: TlsConfig,
+	config: ClientConfig,
+) -> impl Future<Item=TClient, Error=RpcError>
+where
+	TClient: From<RpcChannel>,
+{
+	let connector = match https_connector(tls) {
+		Ok(connector) => connector,
+		Err(err) => return A(future::err(RpcError::Other(err))),
+	};
+	let client = Client::builder().build::<_, hyper::Body>(connector);
+	B(with_client(client, url.to_owned(), config))
+}
+
+/// Send one attempt of `request_str` to `url`, retrying connection failures
+/// (but not timeouts or server errors) until `MAX_CONNECT_ATTEMPTS` attempts
+/// have been made, so
+/// a transient connection refusal (e.g. a server mid-restart) doesn't fail a
+/// call that would have succeeded moments later.
+fn send_request<C>(
+	client: Arc<Client<C>>,
+	url: String,
+	request_str: String,
+	request_timeout: Duration,
+	attempt: usize,
+) -> Box<dyn Future<Item=HyperResponse<Body>, Error=RpcError> + Send>
+where
+	C: Connect + 'static,
+{
+	let request = Request::post(&url)
+		.header(http::header::CONTENT_TYPE, http::header::HeaderValue::from_static("application/json"))
+		.body(request_str.clone().into())
+		.unwrap();
+
+	Box::new(Timeout::new(client.request(request), request_timeout).then(move |result| {
+		let err = match result {
+			Ok(response) => return A(future::ok(response)),
+			Err(err) => err,
+		};
+
+		if err.is_elapsed() {
+			return B(A(future::err(RpcError::Timeout)));
+		}
+
+		match err.into_inner() {
+			Some(hyper_err) if hyper_err.is_connect() && attempt < MAX_CONNECT_ATTEMPTS => {
+				log::warn!(
+					"Connection attempt {} to {} failed, retrying in {:?}: {:?}",
+					attempt, url, CONNECT_RETRY_BACKOFF, hyper_err
+				);
+				B(B(Delay::new(Instant::now() + CONNECT_RETRY_BACKOFF)
+					.map_err(|e| RpcError::Other(e.into()))
+					.and_then(move |_| send_request(client, url, request_str, request_timeout, attempt + 1))))
+			}
+			Some(hyper_err) => B(A(future::err(RpcError::Other(hyper_err.into())))),
+			None => B(A(future::err(RpcError::Other(format_err!("Timer error"))))),
+		}
+	}))
+}
+
+/// Build a fresh `RpcError` carrying the same information as `err`, for
+/// fanning a single transport failure out to every pending call it affects.
+/// `failure::Error` isn't `Clone`, so only the last of several affected
+/// senders gets the original (downcastable) error; the rest get an
+/// equivalent one rebuilt from its message.
+fn fan_out(err: &RpcError) -> RpcError {
+	match err {
+		RpcError::Timeout => RpcError::Timeout,
+		other => RpcError::Other(format_err!("{}", other)),
+	}
+}
+
+fn with_client<TClient, C>(client: Client<C>, url: String, config: ClientConfig) -> impl Future<Item=TClient, Error=RpcError>
+where
+	TClient: From<RpcChannel>,
+	C: Connect + 'static,
+{
+	let max_parallel = config.max_parallel;
+	let request_timeout = config.request_timeout;
+	let client = Arc::new(client);
 
 	let (sender, receiver) = mpsc::channel(0);
 
+	let counter = AtomicUsize::new(1);
+	let pending: Arc<Mutex<Pending>> = Arc::new(Mutex::new(Pending::new()));
+
 	let fut = receiver
 		.map(move |msg: RpcMessage| {
-			let request = jsonrpc_core::Request::Single(Call::MethodCall(MethodCall {
-				jsonrpc: Some(Version::V2),
-				method: msg.method.clone(),
-				params: msg.params.clone(),
-				id: Id::Num(1), // todo: [AJ] assign num
-			}));
+			let next_id = || Id::Num(counter.fetch_add(1, Ordering::Relaxed) as u64);
+
+			let ids: Vec<Id> = match &msg {
+				RpcMessage::Call(_) => vec![next_id()],
+				RpcMessage::Batch(batch) => batch.calls.iter().map(|_| next_id()).collect(),
+			};
+
+			let request = match &msg {
+				RpcMessage::Call(call) => jsonrpc_core::Request::Single(Call::MethodCall(MethodCall {
+					jsonrpc: Some(Version::V2),
+					method: call.method.clone(),
+					params: call.params.clone(),
+					id: ids[0].clone(),
+				})),
+				RpcMessage::Batch(batch) => jsonrpc_core::Request::Batch(
+					batch.calls.iter().zip(&ids).map(|((method, params), id)| {
+						Call::MethodCall(MethodCall {
+							jsonrpc: Some(Version::V2),
+							method: method.clone(),
+							params: params.clone(),
+							id: id.clone(),
+						})
+					}).collect()
+				),
+			};
 			let request_str = serde_json::to_string(&request).expect("Infallible serialization");
 
-			let request = Request::post(&url)
-				.header(http::header::CONTENT_TYPE, http::header::HeaderValue::from_static("application/json"))
-				.body(request_str.into())
-				.unwrap();
+			match msg {
+				RpcMessage::Call(call) => {
+					pending.lock().unwrap().insert(ids[0].clone(), call.sender);
+				}
+				RpcMessage::Batch(batch) => {
+					let (senders, receivers): (Vec<_>, Vec<_>) = ids.iter().map(|_| oneshot::channel()).unzip();
+					{
+						let mut pending = pending.lock().unwrap();
+						for (id, sender) in ids.iter().cloned().zip(senders) {
+							pending.insert(id, sender);
+						}
+					}
+					// Forward each call's individually-routed result into the batch's
+					// single sender, once every call in the batch has resolved.
+					let gathered = future::join_all(receivers.into_iter().map(|receiver| {
+						receiver.then(|result| match result {
+							Ok(result) => Ok(result),
+							Err(err) => Ok(Err(RpcError::Other(err.into()))),
+						})
+					})).then(move |results: Result<Vec<_>, ()>| {
+						if let Err(err) = batch.sender.send(results.expect("errors are folded into Ok above; qed")) {
+							log::warn!("Error resuming asynchronous batch request: {:?}", err);
+						}
+						Ok(())
+					});
+					rt::spawn(gathered);
+				}
+			}
+			let pending = pending.clone();
 
-			client
-				.request(request)
-				.then(move |response| Ok((response, msg)))
+			send_request(client.clone(), url.clone(), request_str, request_timeout, 1)
+				.then(move |response| Ok((ids, pending, response)))
 		})
 		.buffer_unordered(max_parallel)
-		.for_each(|(result, msg)| {
+		.for_each(move |(ids, pending, result)| {
 			let future = match result {
 				Ok(ref res) if !res.status().is_success() => {
 					log::trace!("http result status {}", res.status());
@@ -53,32 +231,57 @@ where
 						RpcError::Other(format_err!("Unexpected response status code: {}", res.status()))
 					))
 				},
+				// `request_timeout` already bounded the time to receive this response's
+				// headers in `send_request`; bound the body read by a fresh
+				// `request_timeout` of its own, or a server that replies promptly then
+				// stalls mid-body would hang the call indefinitely.
 				Ok(res) => B(
-					res.into_body()
-						.map_err(|e| RpcError::ParseError(e.to_string(), e.into()))
-						.concat2()
+					Timeout::new(
+						res.into_body()
+							.map_err(|e| RpcError::ParseError(e.to_string(), e.into()))
+							.concat2(),
+						request_timeout,
+					).then(|result| match result {
+						Ok(chunk) => Ok(chunk),
+						Err(err) => Err(if err.is_elapsed() {
+							RpcError::Timeout
+						} else {
+							match err.into_inner() {
+								Some(err) => err,
+								None => RpcError::Other(format_err!("Timer error")),
+							}
+						}),
+					})
 				),
-				Err(err) => A(future::err(RpcError::Other(err.into()))),
+				Err(err) => A(future::err(err)),
 			};
-			future.then(|result| {
-				let result = result.and_then(|response| {
+			future.then(move |result| {
+				let response = result.and_then(|response| {
 					let response_str = String::from_utf8_lossy(response.as_ref()).into_owned();
 					serde_json::from_str::<Response>(&response_str)
 						.map_err(|e| RpcError::ParseError(e.to_string(), e.into()))
-						.and_then(|response| {
-							let output: Output = match response {
-								Response::Single(output) => output,
-								Response::Batch(_) => unreachable!(),
-							};
-							let value: Result<serde_json::Value, Error> = output.into();
-							value.map_err(|e| RpcError::JsonRpcError(e))
-						})
-					});
-
-				if let Err(err) = msg.sender.send(result) {
-					log::warn!("Error resuming asynchronous request: {:?}", err);
+				});
+
+				match response {
+					Ok(response) => request_response(&mut pending.lock().unwrap(), &ids, response),
+					// A failure here is the transport's, not the server's: the pending
+					// call(s) are failed directly and the dispatch loop below keeps
+					// running so later `RpcMessage`s are still serviced.
+					Err(mut err) => {
+						let mut pending = pending.lock().unwrap();
+						let last = ids.len().saturating_sub(1);
+						for (i, id) in ids.into_iter().enumerate() {
+							if let Some(sender) = pending.remove(&id) {
+								let to_send = if i == last { std::mem::replace(&mut err, RpcError::Timeout) } else { fan_out(&err) };
+								if let Err(err) = sender.send(Err(to_send)) {
+									log::warn!("Error resuming asynchronous request: {:?}", err);
+								}
+							}
+						}
+					}
 				}
-				Ok(())
+
+				Ok(()) as Result<(), RpcError>
 			})
 		});
 
@@ -151,6 +354,10 @@ mod tests {
 			_ => Ok(Value::String("world".into())),
 		});
 		io.add_method("fail", |_: Params| Err(Error::new(ErrorCode::ServerError(-34))));
+		io.add_method("slow", |_: Params| {
+			std::thread::sleep(Duration::from_millis(200));
+			Ok(Value::String("done".into()))
+		});
 
 		io
 	}
@@ -171,6 +378,15 @@ mod tests {
 		fn fail(&self) -> impl Future<Item=(), Error=RpcError> {
 			self.0.call_method("fail", "()", ())
 		}
+		fn hello_batch(&self, msgs: &[&'static str]) -> impl Future<Item=Vec<Result<Value, RpcError>>, Error=RpcError> {
+			let calls = msgs.iter()
+				.map(|msg| ("hello", Params::Array(vec![Value::String((*msg).into())])))
+				.collect();
+			self.0.call_batch(calls)
+		}
+		fn slow(&self) -> impl Future<Item=String, Error=RpcError> {
+			self.0.call_method("slow", "String", ())
+		}
 	}
 
 	#[test]
@@ -201,6 +417,163 @@ mod tests {
 		assert_eq!("hello http", result);
 	}
 
+	#[test]
+	fn sends_batch_request() {
+		crate::logger::init_log();
+
+		// given
+		let server = TestServer::serve(id);
+		let (tx, rx) = std::sync::mpsc::channel();
+
+		// when
+		let run =
+			http(&server.uri)
+				.and_then(|client: TestClient| {
+					client.hello_batch(&["alice", "bob"])
+						.and_then(move |result| {
+							drop(client);
+							let _ = tx.send(result);
+							Ok(())
+						})
+				})
+				.map_err(|e| log::error!("RPC Client error: {:?}", e));
+
+		rt::run(run);
+
+		// then
+		let results = rx.recv_timeout(Duration::from_secs(3)).unwrap();
+		let results: Vec<String> = results.into_iter()
+			.map(|result| serde_json::from_value(result.unwrap()).unwrap())
+			.collect();
+		assert_eq!(results, vec!["hello alice".to_owned(), "hello bob".to_owned()]);
+	}
+
+	#[test]
+	fn batch_response_without_matching_ids_falls_back_to_positional_order() {
+		crate::logger::init_log();
+
+		// given: a non-conformant server that answers a 2-call batch with
+		// only one output, carrying a null id -- the spec-mandated id for a
+		// batch-level parse failure, but also what a buggy server can send.
+		use std::io::{Read, Write};
+		use std::net::TcpListener;
+
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let addr = listener.local_addr().unwrap();
+		std::thread::spawn(move || {
+			if let Ok((mut stream, _)) = listener.accept() {
+				let mut buf = [0u8; 4096];
+				let _ = stream.read(&mut buf);
+				let body = br#"[{"jsonrpc":"2.0","result":"hello alice","id":null}]"#;
+				let header = format!(
+					"HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n",
+					body.len()
+				);
+				let _ = stream.write_all(header.as_bytes());
+				let _ = stream.write_all(body);
+				let _ = stream.flush();
+			}
+		});
+
+		let (tx, rx) = std::sync::mpsc::channel();
+
+		// when
+		let run =
+			http(&format!("http://{}", addr))
+				.and_then(|client: TestClient| {
+					client.hello_batch(&["alice", "bob"])
+						.then(move |res| {
+							let _ = tx.send(res);
+							Ok(())
+						})
+				})
+				.map_err(|e| log::error!("RPC Client error: {:?}", e));
+		rt::run(run);
+
+		// then: the first call is matched positionally and resolves, the
+		// second never got an output at all and is failed instead of hanging
+		let results = rx.recv_timeout(Duration::from_secs(3)).unwrap().unwrap();
+		assert_eq!(results.len(), 2);
+		assert_eq!(results[0].as_ref().unwrap(), &Value::String("hello alice".into()));
+		assert!(results[1].is_err());
+	}
+
+	#[test]
+	fn times_out_slow_request() {
+		crate::logger::init_log();
+
+		// given
+		let server = TestServer::serve(id);
+		let (tx, rx) = std::sync::mpsc::channel();
+		let config = ClientConfig { request_timeout: Duration::from_millis(50), ..ClientConfig::default() };
+
+		// when
+		let run =
+			http_with_config(&server.uri, config)
+				.and_then(|client: TestClient| {
+					client
+						.slow()
+						.then(move |res| {
+							let _ = tx.send(res);
+							Ok(())
+						})
+				})
+				.map_err(|e| log::error!("RPC Client error: {:?}", e));
+		rt::run(run);
+
+		// then
+		let res = rx.recv_timeout(Duration::from_secs(3)).unwrap();
+		assert!(matches!(res, Err(RpcError::Timeout)), "expected RpcError::Timeout, got {:?}", res);
+	}
+
+	#[test]
+	fn times_out_when_body_stalls_mid_stream() {
+		crate::logger::init_log();
+
+		// given: a raw server that sends headers and part of the body
+		// promptly, then stalls past the configured timeout before finishing
+		// it -- unlike `times_out_slow_request`, which stalls before sending
+		// anything, this exercises a stall in the body-read stage itself.
+		use std::io::{Read, Write};
+		use std::net::TcpListener;
+
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let addr = listener.local_addr().unwrap();
+		std::thread::spawn(move || {
+			if let Ok((mut stream, _)) = listener.accept() {
+				let mut buf = [0u8; 1024];
+				let _ = stream.read(&mut buf);
+				let _ = stream.write_all(
+					b"HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: 20\r\n\r\n{\"jsonrpc\":\"2.0\""
+				);
+				let _ = stream.flush();
+				std::thread::sleep(Duration::from_millis(500));
+				// dropped without ever completing the body
+			}
+		});
+
+		let (tx, rx) = std::sync::mpsc::channel();
+		let config = ClientConfig { request_timeout: Duration::from_millis(50), ..ClientConfig::default() };
+
+		// when
+		let run =
+			http_with_config(&format!("http://{}", addr), config)
+				.and_then(|client: TestClient| {
+					client
+						.hello("http")
+						.then(move |res| {
+							let _ = tx.send(res);
+							Ok(())
+						})
+				})
+				.map_err(|e| log::error!("RPC Client error: {:?}", e));
+		rt::run(run);
+
+		// then
+		let res = rx.recv_timeout(Duration::from_secs(3)).unwrap();
+		assert!(matches!(res, Err(RpcError::Timeout)), "expected RpcError::Timeout, got {:?}", res);
+	}
+
 	#[test]
 	fn handles_server_error() {
 		crate::logger::init_log();
@@ -233,6 +606,36 @@ mod tests {
 		}
 	}
 
+	#[test]
+	fn correlates_concurrent_calls_by_id() {
+		crate::logger::init_log();
+
+		// given
+		let server = TestServer::serve(id);
+		let (tx, rx) = std::sync::mpsc::channel();
+
+		// when: two calls are in flight at once, so their responses can come
+		// back in either order; each must still resolve with its own result.
+		let run =
+			http(&server.uri)
+				.and_then(|client: TestClient| {
+					client.hello("alice")
+						.join(client.hello("bob"))
+						.then(move |result| {
+							let _ = tx.send(result);
+							Ok(())
+						})
+				})
+				.map_err(|e| log::error!("RPC Client error: {:?}", e));
+
+		rt::run(run);
+
+		// then
+		let (alice, bob) = rx.recv_timeout(Duration::from_secs(3)).unwrap().unwrap();
+		assert_eq!("hello alice", alice);
+		assert_eq!("hello bob", bob);
+	}
+
 	#[test]
 	fn handles_connection_refused_error() {
 		// given
@@ -271,7 +674,6 @@ mod tests {
 	}
 
 	#[test]
-	#[ignore] // todo: [AJ] make it pass
 	fn client_still_works_after_http_connect_error() {
 		// given
 		let mut server = TestServer::serve(id);
@@ -295,7 +697,7 @@ mod tests {
 					.and_then(move |_| {
 						server.start(); // todo: make the server start on the main thread
 						client
-							.hello("http2")
+							.hello("http")
 							.then(move |res| {
 								let _ = tx2.send(res);
 								Ok(())
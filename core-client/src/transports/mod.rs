@@ -0,0 +1,65 @@
+//! Transports a [`TypedClient`](crate::TypedClient) can be built on top of.
+
+use std::collections::HashMap;
+
+use failure::format_err;
+use futures::sync::oneshot;
+use jsonrpc_core::{Id, Response};
+use serde_json::Value;
+
+use crate::RpcError;
+
+pub mod http;
+pub mod tls;
+pub mod ws;
+
+/// Calls that have been dispatched to the server and are waiting for their
+/// matching response, keyed by the `Id` they were sent with.
+pub type Pending = HashMap<Id, oneshot::Sender<Result<Value, RpcError>>>;
+
+/// Route a (possibly batched) `Response` to the `pending` calls it answers.
+///
+/// Each `Output`'s `id` is looked up in `pending` and, if found, removed and
+/// resolved with the call's result. A conformant server always echoes back
+/// the id it was sent, but a malformed one may send back an id that matches
+/// nothing pending (commonly a null id on a batch-level parse failure); when
+/// `ids` holds the ids this response is expected to answer — knowable for a
+/// single HTTP request/response pair, but not for a multiplexed connection
+/// like the WebSocket transport's, which passes an empty slice — such an
+/// output falls back to positional order against `ids`. Once every output
+/// has been matched this way, anything left in `ids` got no answer at all
+/// (the server returned too few outputs) and is failed outright, rather than
+/// left to hang its `oneshot::Receiver` forever.
+pub fn request_response(pending: &mut Pending, ids: &[Id], response: Response) {
+	let outputs = match response {
+		Response::Single(output) => vec![output],
+		Response::Batch(outputs) => outputs,
+	};
+
+	for (index, output) in outputs.into_iter().enumerate() {
+		let id = output.id().clone();
+		let id = match pending.contains_key(&id) {
+			true => id,
+			false => ids.get(index).cloned().unwrap_or(id),
+		};
+
+		match pending.remove(&id) {
+			Some(sender) => {
+				let result: Result<Value, jsonrpc_core::Error> = output.into();
+				if let Err(err) = sender.send(result.map_err(RpcError::JsonRpcError)) {
+					log::warn!("Error resuming asynchronous request: {:?}", err);
+				}
+			}
+			None => log::warn!("Got response for unknown request id: {:?}", id),
+		}
+	}
+
+	for id in ids {
+		if let Some(sender) = pending.remove(id) {
+			let message = format_err!("Server response did not include a result for request id {:?}", id);
+			if let Err(err) = sender.send(Err(RpcError::Other(message))) {
+				log::warn!("Error resuming asynchronous request: {:?}", err);
+			}
+		}
+	}
+}
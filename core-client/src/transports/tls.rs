@@ -0,0 +1,118 @@
+//! TLS configuration for the `https` transport.
+
+use std::{io::Cursor, sync::Arc};
+
+use failure::format_err;
+use hyper::client::HttpConnector;
+use hyper_rustls::HttpsConnector;
+use rustls::{internal::pemfile, ClientConfig as RustlsClientConfig};
+
+/// Certificate trust and client authentication settings for an HTTPS client,
+/// analogous to garage's `TlsConfig`.
+#[derive(Default, Clone)]
+pub struct TlsConfig {
+	/// Extra root certificates (PEM-encoded) to trust, on top of the
+	/// bundled `webpki-roots` set. Use this to talk to a server with a
+	/// self-signed or privately-issued certificate.
+	pub extra_root_certs_pem: Vec<Vec<u8>>,
+	/// A client certificate chain and private key (both PEM-encoded) to
+	/// present for mutual TLS, if the server requires one.
+	pub client_cert_pem: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+pub(crate) fn https_connector(tls: TlsConfig) -> Result<HttpsConnector<HttpConnector>, failure::Error> {
+	let mut rustls_config = RustlsClientConfig::new();
+	rustls_config.root_store.add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+
+	for pem in &tls.extra_root_certs_pem {
+		let (valid, _invalid) = rustls_config.root_store
+			.add_pem_file(&mut Cursor::new(pem))
+			.map_err(|()| format_err!("Invalid extra root certificate"))?;
+		if valid == 0 {
+			return Err(format_err!("Invalid extra root certificate"));
+		}
+	}
+
+	if let Some((cert_pem, key_pem)) = tls.client_cert_pem {
+		let certs = pemfile::certs(&mut Cursor::new(&cert_pem))
+			.map_err(|()| format_err!("Invalid client certificate"))?;
+		if certs.is_empty() {
+			return Err(format_err!("Invalid client certificate"));
+		}
+		let mut keys = pemfile::pkcs8_private_keys(&mut Cursor::new(&key_pem))
+			.map_err(|()| format_err!("Invalid client private key"))?;
+		if keys.is_empty() {
+			// Most client certs still ship an RSA (PKCS1) key rather than PKCS8.
+			keys = pemfile::rsa_private_keys(&mut Cursor::new(&key_pem))
+				.map_err(|()| format_err!("Invalid client private key"))?;
+		}
+		let key = keys.pop().ok_or_else(|| format_err!("No private key found in client_cert_pem"))?;
+		rustls_config.set_single_client_cert(certs, key)
+			.map_err(|e| format_err!("Invalid client certificate/key pair: {}", e))?;
+	}
+
+	let mut http = HttpConnector::new(4);
+	http.enforce_http(false);
+	Ok(HttpsConnector::from((http, Arc::new(rustls_config))))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	// A self-signed certificate and two equivalent private keys for it, one
+	// PKCS8 and one PKCS1 (`RSA PRIVATE KEY`), covering the still-common
+	// format that `pkcs8_private_keys` alone used to reject (see the PKCS1
+	// fallback added above).
+	const CERT_PEM: &[u8] = include_bytes!("testdata/cert.pem");
+	const KEY_PKCS8_PEM: &[u8] = include_bytes!("testdata/key_pkcs8.pem");
+	const KEY_PKCS1_PEM: &[u8] = include_bytes!("testdata/key_pkcs1.pem");
+
+	#[test]
+	fn builds_connector_with_pkcs8_client_key() {
+		let tls = TlsConfig {
+			extra_root_certs_pem: vec![],
+			client_cert_pem: Some((CERT_PEM.to_vec(), KEY_PKCS8_PEM.to_vec())),
+		};
+		https_connector(tls).expect("a self-signed cert with a PKCS8 key should build a connector");
+	}
+
+	#[test]
+	fn builds_connector_with_pkcs1_client_key() {
+		let tls = TlsConfig {
+			extra_root_certs_pem: vec![],
+			client_cert_pem: Some((CERT_PEM.to_vec(), KEY_PKCS1_PEM.to_vec())),
+		};
+		https_connector(tls).expect("a self-signed cert with a PKCS1 key should build a connector via the PKCS8-then-PKCS1 fallback");
+	}
+
+	#[test]
+	fn rejects_malformed_extra_root_cert() {
+		let tls = TlsConfig {
+			extra_root_certs_pem: vec![b"not a pem file".to_vec()],
+			client_cert_pem: None,
+		};
+		let err = https_connector(tls).expect_err("malformed PEM should be rejected");
+		assert_eq!(err.to_string(), "Invalid extra root certificate");
+	}
+
+	#[test]
+	fn rejects_malformed_client_certificate() {
+		let tls = TlsConfig {
+			extra_root_certs_pem: vec![],
+			client_cert_pem: Some((b"not a cert".to_vec(), KEY_PKCS8_PEM.to_vec())),
+		};
+		let err = https_connector(tls).expect_err("malformed client certificate PEM should be rejected");
+		assert_eq!(err.to_string(), "Invalid client certificate");
+	}
+
+	#[test]
+	fn rejects_malformed_client_key() {
+		let tls = TlsConfig {
+			extra_root_certs_pem: vec![],
+			client_cert_pem: Some((CERT_PEM.to_vec(), b"not a key".to_vec())),
+		};
+		let err = https_connector(tls).expect_err("malformed client key PEM should be rejected");
+		assert_eq!(err.to_string(), "No private key found in client_cert_pem");
+	}
+}
@@ -0,0 +1,403 @@
+//! WebSocket client.
+//!
+//! Unlike the stateless HTTP transport, a single WebSocket connection stays
+//! open and is shared by every call. Calls are still correlated through the
+//! same `Id`-keyed [`Pending`] map, but the read loop must also recognise
+//! messages the server sends that *aren't* an answer to one of our calls —
+//! a `jsonrpc_core::Call` pushed by the server on its own, typically a
+//! subscription notification. Those are surfaced to callers as a `Stream`
+//! on the returned handle, which a request/response-only transport like
+//! HTTP has no way to support.
+
+use std::sync::{atomic::{AtomicBool, AtomicUsize, Ordering}, Arc, Mutex};
+
+use failure::format_err;
+use futures::{
+	sync::{mpsc, oneshot},
+	Future, Sink, Stream,
+};
+use hyper::rt;
+use jsonrpc_core::{Call, Id, MethodCall, Request as CoreRequest, Response, Version};
+use websocket::{ClientBuilder, OwnedMessage};
+
+use crate::{RpcChannel, RpcError, RpcMessage};
+use super::{request_response, Pending};
+
+/// A handle to a WebSocket-backed client.
+///
+/// Regular calls are made through `client`, the same typed client any other
+/// transport yields; `notifications` additionally yields every `Call`
+/// the server pushes without being asked, in arrival order.
+pub struct WsClient<TClient> {
+	/// The typed client calls are made through.
+	pub client: TClient,
+	/// Calls pushed by the server that were not a response to one of ours —
+	/// e.g. subscription notifications.
+	pub notifications: mpsc::Receiver<Call>,
+}
+
+/// Connect a WebSocket client to `url`.
+pub fn ws<TClient>(url: &str) -> impl Future<Item=WsClient<TClient>, Error=RpcError>
+where
+	TClient: From<RpcChannel>,
+{
+	let counter = AtomicUsize::new(1);
+	let pending: Arc<Mutex<Pending>> = Arc::new(Mutex::new(Pending::new()));
+	let (notify_sender, notify_receiver) = mpsc::channel(64);
+
+	ClientBuilder::new(url)
+		.map_err(|e| RpcError::Other(e.into()))
+		.and_then(|builder| builder.async_connect_insecure().map_err(|e| RpcError::Other(e.into())))
+		.map(move |(duplex, _headers)| {
+			let (ws_sink, ws_stream) = duplex.split();
+			let (sender, receiver) = mpsc::channel(0);
+
+			let pending_for_reader = pending.clone();
+			let pending_on_close = pending.clone();
+			let closed = Arc::new(AtomicBool::new(false));
+			let closed_on_close = closed.clone();
+			let reader = ws_stream
+				.map_err(|e| RpcError::Other(e.into()))
+				.for_each(move |message| {
+					let text = match message {
+						OwnedMessage::Text(text) => text,
+						_ => return Ok(()),
+					};
+
+					// A response carries the `id` of one of our pending calls;
+					// anything else is the server speaking first. Unlike HTTP's
+					// one-shot request/response, this connection is multiplexed, so
+					// there's no set of ids to positionally fall back against if a
+					// response's id doesn't match anything pending.
+					match serde_json::from_str::<Response>(&text) {
+						Ok(response) => request_response(&mut pending_for_reader.lock().unwrap(), &[], response),
+						Err(_) => match serde_json::from_str::<Call>(&text) {
+							Ok(call) => if let Err(err) = notify_sender.clone().try_send(call) {
+								log::warn!("Dropped server notification, receiver lagging: {:?}", err);
+							},
+							Err(err) => log::warn!("Failed to parse inbound message {:?}: {}", text, err),
+						},
+					}
+
+					Ok(())
+				})
+				.then(move |result| {
+					// The socket closed, cleanly or with an error, while calls may
+					// still be outstanding; nothing will ever answer them now, so
+					// fail every pending call instead of leaving its receiver
+					// hanging on a dead connection forever.
+					let message = match result {
+						Ok(()) => "WebSocket connection closed".to_owned(),
+						Err(err) => err.to_string(),
+					};
+					closed_on_close.store(true, Ordering::SeqCst);
+					for (_, sender) in pending_on_close.lock().unwrap().drain() {
+						let _ = sender.send(Err(RpcError::Other(format_err!("{}", message))));
+					}
+					Ok(()) as Result<(), ()>
+				});
+			rt::spawn(reader);
+
+			let pending = pending.clone();
+			let writer = receiver
+				.map(move |msg: RpcMessage| {
+					let next_id = || Id::Num(counter.fetch_add(1, Ordering::Relaxed) as u64);
+
+					let (ids, request): (Vec<Id>, CoreRequest) = match &msg {
+						RpcMessage::Call(call) => {
+							let id = next_id();
+							(vec![id.clone()], CoreRequest::Single(Call::MethodCall(MethodCall {
+								jsonrpc: Some(Version::V2),
+								method: call.method.clone(),
+								params: call.params.clone(),
+								id,
+							})))
+						}
+						RpcMessage::Batch(batch) => {
+							let calls: Vec<_> = batch.calls.iter().map(|(method, params)| {
+								let id = next_id();
+								(id, Call::MethodCall(MethodCall {
+									jsonrpc: Some(Version::V2),
+									method: method.clone(),
+									params: params.clone(),
+									id,
+								}))
+							}).collect();
+							let ids = calls.iter().map(|(id, _)| id.clone()).collect();
+							(ids, CoreRequest::Batch(calls.into_iter().map(|(_, call)| call).collect()))
+						}
+					};
+
+					match msg {
+						RpcMessage::Call(call) => {
+							pending.lock().unwrap().insert(ids[0].clone(), call.sender);
+						}
+						RpcMessage::Batch(batch) => {
+							let (senders, receivers): (Vec<_>, Vec<_>) = ids.iter().map(|_| oneshot::channel()).unzip();
+							{
+								let mut pending = pending.lock().unwrap();
+								for (id, sender) in ids.iter().cloned().zip(senders) {
+									pending.insert(id, sender);
+								}
+							}
+							let gathered = futures::future::join_all(receivers.into_iter().map(|receiver| {
+								receiver.then(|result| match result {
+									Ok(result) => Ok(result),
+									Err(err) => Ok(Err(RpcError::Other(err.into()))),
+								})
+							})).then(move |results: Result<Vec<_>, ()>| {
+								if let Err(err) = batch.sender.send(results.expect("errors are folded into Ok above; qed")) {
+									log::warn!("Error resuming asynchronous batch request: {:?}", err);
+								}
+								Ok(())
+							});
+							rt::spawn(gathered);
+						}
+					}
+
+					// A call registered after the reader drained `pending` on close
+					// would never be answered; fail it here instead.
+					if closed.load(Ordering::SeqCst) {
+						for (_, sender) in pending.lock().unwrap().drain() {
+							let _ = sender.send(Err(RpcError::Other(format_err!("WebSocket connection closed"))));
+						}
+					}
+
+					let request_str = serde_json::to_string(&request).expect("Infallible serialization");
+					OwnedMessage::Text(request_str)
+				})
+				.map_err(|()| -> websocket::WebSocketError { unreachable!("mpsc receiver never errors") })
+				.forward(ws_sink)
+				.map(|_| ())
+				.map_err(|e| log::error!("WebSocket write error: {:?}", e));
+			rt::spawn(writer);
+
+			WsClient {
+				client: TClient::from(sender),
+				notifications: notify_receiver,
+			}
+		})
+}
+
+#[cfg(test)]
+mod tests {
+	use std::net::SocketAddr;
+	use std::time::Duration;
+
+	use jsonrpc_core::{ErrorCode, Params, Value};
+	use websocket::sync::Server;
+
+	use super::*;
+	use crate::*;
+
+	#[derive(Clone)]
+	struct TestClient(TypedClient);
+
+	impl From<RpcChannel> for TestClient {
+		fn from(channel: RpcChannel) -> Self {
+			TestClient(channel.into())
+		}
+	}
+
+	impl TestClient {
+		fn hello(&self, msg: &'static str) -> impl Future<Item=String, Error=RpcError> {
+			self.0.call_method("hello", "String", (msg,))
+		}
+		fn fail(&self) -> impl Future<Item=(), Error=RpcError> {
+			self.0.call_method("fail", "()", ())
+		}
+	}
+
+	/// Answer every `hello`/`fail` call on one accepted connection. If
+	/// `push_notification` is set, push one unsolicited `Call` first, the
+	/// same way a subscription notification would arrive. If `close_after`
+	/// is set, close the socket after that many calls have been answered.
+	fn serve(push_notification: bool, close_after: Option<usize>) -> SocketAddr {
+		let server = Server::bind("127.0.0.1:0").unwrap();
+		let addr = server.local_addr().unwrap();
+
+		std::thread::spawn(move || {
+			let connection = match server.into_iter().next() {
+				Some(Ok(connection)) => connection,
+				_ => return,
+			};
+			let client = match connection.accept() {
+				Ok(client) => client,
+				Err(_) => return,
+			};
+			let (mut receiver, mut sender) = client.split().unwrap();
+
+			if push_notification {
+				let notification = Call::MethodCall(MethodCall {
+					jsonrpc: Some(Version::V2),
+					method: "subscription".to_owned(),
+					params: Params::None,
+					id: Id::Num(0),
+				});
+				let text = serde_json::to_string(&notification).unwrap();
+				let _ = sender.send_message(&OwnedMessage::Text(text));
+			}
+
+			let mut handled = 0;
+			for message in receiver.incoming_messages() {
+				let message = match message {
+					Ok(message) => message,
+					Err(_) => break,
+				};
+				let text = match message {
+					OwnedMessage::Text(text) => text,
+					OwnedMessage::Close(_) => break,
+					_ => continue,
+				};
+				let request: CoreRequest = serde_json::from_str(&text).unwrap();
+				let call = match request {
+					CoreRequest::Single(Call::MethodCall(call)) => call,
+					_ => continue,
+				};
+
+				let output = if call.method == "fail" {
+					jsonrpc_core::Output::Failure(jsonrpc_core::Failure {
+						jsonrpc: Some(Version::V2),
+						error: jsonrpc_core::Error::new(ErrorCode::ServerError(-34)),
+						id: call.id,
+					})
+				} else {
+					let msg: (String,) = call.params.parse().unwrap_or_default();
+					jsonrpc_core::Output::Success(jsonrpc_core::Success {
+						jsonrpc: Some(Version::V2),
+						result: Value::String(format!("hello {}", msg.0)),
+						id: call.id,
+					})
+				};
+				let response = Response::Single(output);
+				let text = serde_json::to_string(&response).unwrap();
+				let _ = sender.send_message(&OwnedMessage::Text(text));
+
+				handled += 1;
+				if close_after == Some(handled) {
+					let _ = sender.send_message(&OwnedMessage::Close(None));
+					break;
+				}
+			}
+		});
+
+		addr
+	}
+
+	#[test]
+	fn should_work() {
+		crate::logger::init_log();
+
+		// given
+		let addr = serve(false, None);
+		let (tx, rx) = std::sync::mpsc::channel();
+
+		// when
+		let run = ws(&format!("ws://{}", addr))
+			.and_then(|client: WsClient<TestClient>| {
+				client.client.hello("ws")
+					.then(move |result| {
+						let _ = tx.send(result);
+						Ok(())
+					})
+			})
+			.map_err(|e| log::error!("RPC Client error: {:?}", e));
+
+		rt::run(run);
+
+		// then
+		let result = rx.recv_timeout(Duration::from_secs(3)).unwrap().unwrap();
+		assert_eq!("hello ws", result);
+	}
+
+	#[test]
+	fn handles_server_error() {
+		crate::logger::init_log();
+
+		// given
+		let addr = serve(false, None);
+		let (tx, rx) = std::sync::mpsc::channel();
+
+		// when
+		let run = ws(&format!("ws://{}", addr))
+			.and_then(|client: WsClient<TestClient>| {
+				client.client.fail()
+					.then(move |result| {
+						let _ = tx.send(result);
+						Ok(())
+					})
+			})
+			.map_err(|e| log::error!("RPC Client error: {:?}", e));
+
+		rt::run(run);
+
+		// then
+		let res = rx.recv_timeout(Duration::from_secs(3)).unwrap();
+		if let Err(RpcError::JsonRpcError(err)) = res {
+			assert_eq!(err.code, ErrorCode::ServerError(-34));
+		} else {
+			panic!("Expected JsonRpcError. Received {:?}", res)
+		}
+	}
+
+	#[test]
+	fn disambiguates_pushed_notifications_from_responses() {
+		crate::logger::init_log();
+
+		// given: the server pushes a notification before the call is even answered
+		let addr = serve(true, None);
+		let (tx, rx) = std::sync::mpsc::channel();
+
+		// when
+		let run = ws(&format!("ws://{}", addr))
+			.and_then(|client: WsClient<TestClient>| {
+				let WsClient { client, notifications } = client;
+				client.hello("ws")
+					.then(move |result| {
+						let _ = tx.send(result);
+						Ok(()) as Result<(), RpcError>
+					})
+					.join(notifications.into_future().then(|result| match result {
+						Ok((Some(_call), _)) => Ok(()),
+						_ => Err(RpcError::Other(format_err!("no notification received"))),
+					}))
+			})
+			.map_err(|e| log::error!("RPC Client error: {:?}", e));
+
+		rt::run(run);
+
+		// then: the call still resolves with its own result, not the
+		// notification, and the notification surfaced on its own channel
+		let result = rx.recv_timeout(Duration::from_secs(3)).unwrap().unwrap();
+		assert_eq!("hello ws", result);
+	}
+
+	#[test]
+	fn fails_pending_calls_when_connection_closes() {
+		crate::logger::init_log();
+
+		// given: the server closes the socket right after answering one call,
+		// so a second call made on the same client never gets a response.
+		let addr = serve(false, Some(1));
+		let (tx, rx) = std::sync::mpsc::channel();
+
+		// when
+		let run = ws(&format!("ws://{}", addr))
+			.and_then(move |client: WsClient<TestClient>| {
+				client.client.hello("first")
+					.and_then(move |_| client.client.hello("second"))
+					.then(move |result| {
+						let _ = tx.send(result);
+						Ok(())
+					})
+			})
+			.map_err(|e| log::error!("RPC Client error: {:?}", e));
+
+		rt::run(run);
+
+		// then: the call left hanging on the dead connection is failed
+		// instead of its receiver waiting forever.
+		let result = rx.recv_timeout(Duration::from_secs(3)).unwrap();
+		assert!(result.is_err(), "expected the second call to fail, got {:?}", result);
+	}
+}